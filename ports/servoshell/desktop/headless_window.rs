@@ -4,12 +4,17 @@
 
 //! A headless window implementation.
 
-use std::cell::Cell;
-use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::RwLock;
 
 use euclid::num::Zero;
 use euclid::{Box2D, Length, Point2D, Rotation3D, Scale, Size2D, UnknownUnit, Vector3D};
+use gleam::gl;
+use image::ImageEncoder;
 use log::warn;
 use servo::compositing::windowing::{
     AnimationState, EmbedderCoordinates, EmbedderEvent, WindowMethods,
@@ -23,15 +28,132 @@ use surfman::{Connection, Context, Device, SurfaceType};
 
 use crate::desktop::window_trait::WindowPortsMethods;
 
+/// Monotonic source of stable, process-unique headless window identifiers.
+static NEXT_HEADLESS_WINDOW_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A stable identifier for a headless [`Window`].
+///
+/// winit only hands out real `winit::window::WindowId`s for windows it created
+/// itself (everything else is `WindowId::dummy()`), so headless windows are
+/// addressed by this id instead when more than one exists.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct HeadlessWindowId(u64);
+
+/// Tracks the set of live headless windows so the embedder can create and route
+/// events to more than one at a time.
+///
+/// The registry holds only a [`Weak`] to each window so that tracking does not
+/// keep a window (and the surfman `RenderingContext` it owns) alive: the window
+/// is freed as soon as the embedder drops its own handle, and the stale entry is
+/// cleaned up on the next lookup or removal of its id.
+#[derive(Default)]
+pub struct HeadlessWindowRegistry {
+    windows: HashMap<HeadlessWindowId, Weak<Window>>,
+}
+
+impl HeadlessWindowRegistry {
+    pub fn new() -> HeadlessWindowRegistry {
+        HeadlessWindowRegistry::default()
+    }
+
+    /// Create a headless window, mint a fresh id for it and track it.
+    pub fn create_window(
+        &mut self,
+        size: Size2D<u32, DeviceIndependentPixel>,
+        device_pixel_ratio_override: Option<f32>,
+    ) -> (HeadlessWindowId, Rc<dyn WindowPortsMethods>) {
+        let window = Window::new_headless(size, device_pixel_ratio_override);
+        let id = window.headless_id();
+        self.windows.insert(id, Rc::downgrade(&window));
+        (id, window)
+    }
+
+    /// Look up a tracked window by id, dropping the entry if the window has
+    /// since been freed.
+    pub fn get(&mut self, id: HeadlessWindowId) -> Option<Rc<dyn WindowPortsMethods>> {
+        match self.windows.get(&id).and_then(Weak::upgrade) {
+            Some(window) => Some(window as Rc<dyn WindowPortsMethods>),
+            None => {
+                self.windows.remove(&id);
+                None
+            },
+        }
+    }
+
+    /// Stop tracking a window, returning it if it was still alive.
+    pub fn remove(&mut self, id: HeadlessWindowId) -> Option<Rc<dyn WindowPortsMethods>> {
+        self.windows
+            .remove(&id)
+            .and_then(|window| window.upgrade())
+            .map(|window| window as Rc<dyn WindowPortsMethods>)
+    }
+
+    /// The ids of every window that is still alive.
+    pub fn ids(&self) -> Vec<HeadlessWindowId> {
+        self.windows
+            .iter()
+            .filter(|(_, window)| window.strong_count() > 0)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+thread_local! {
+    /// The live headless windows owned by the current thread. Headless windows
+    /// are `!Send` (they hold an `Rc`-backed `RenderingContext`), so the
+    /// registry is per-thread rather than a global.
+    static HEADLESS_WINDOWS: RefCell<HeadlessWindowRegistry> =
+        RefCell::new(HeadlessWindowRegistry::new());
+}
+
+/// Create a headless window, tracking it in the per-thread registry so the
+/// embedder can address it by id later.
+pub fn create_headless_window(
+    size: Size2D<u32, DeviceIndependentPixel>,
+    device_pixel_ratio_override: Option<f32>,
+) -> (HeadlessWindowId, Rc<dyn WindowPortsMethods>) {
+    HEADLESS_WINDOWS.with(|registry| {
+        registry
+            .borrow_mut()
+            .create_window(size, device_pixel_ratio_override)
+    })
+}
+
+/// Look up a tracked headless window by id.
+pub fn headless_window(id: HeadlessWindowId) -> Option<Rc<dyn WindowPortsMethods>> {
+    HEADLESS_WINDOWS.with(|registry| registry.borrow_mut().get(id))
+}
+
+/// Stop tracking a headless window, returning it if it was still alive.
+pub fn remove_headless_window(id: HeadlessWindowId) -> Option<Rc<dyn WindowPortsMethods>> {
+    HEADLESS_WINDOWS.with(|registry| registry.borrow_mut().remove(id))
+}
+
+/// The ids of every headless window tracked on this thread that is still alive.
+pub fn headless_window_ids() -> Vec<HeadlessWindowId> {
+    HEADLESS_WINDOWS.with(|registry| registry.borrow().ids())
+}
+
 pub struct Window {
+    id: HeadlessWindowId,
     rendering_context: RenderingContext,
     animation_state: Cell<AnimationState>,
     fullscreen: Cell<bool>,
-    device_pixel_ratio_override: Option<Scale<f32, DeviceIndependentPixel, DevicePixel>>,
+    device_pixel_ratio_override: Cell<Option<Scale<f32, DeviceIndependentPixel, DevicePixel>>>,
     inner_size: Cell<DeviceIntSize>,
+    page_zoom: Cell<f32>,
     screen_size: Size2D<i32, DeviceIndependentPixel>,
     window_rect: Box2D<i32, DeviceIndependentPixel>,
     event_queue: RwLock<Vec<EmbedderEvent>>,
+    event_sender: Sender<EmbedderEvent>,
+    external_events: Receiver<EmbedderEvent>,
+    xr_rotation: Cell<Rotation3D<f32, UnknownUnit, UnknownUnit>>,
+    xr_translation: Cell<Vector3D<f32, UnknownUnit>>,
+    /// The surface-texture backing the most recently vended WebXR render
+    /// target. surfman requires surfaces to be torn down explicitly, and the
+    /// texture id handed to WebXR is only valid while this stays alive, so the
+    /// window retains ownership rather than letting it drop.
+    xr_surface_texture: RefCell<Option<surfman::SurfaceTexture>>,
 }
 
 impl Window {
@@ -40,6 +162,19 @@ impl Window {
         size: Size2D<u32, DeviceIndependentPixel>,
         device_pixel_ratio_override: Option<f32>,
     ) -> Rc<dyn WindowPortsMethods> {
+        let (_id, window) = create_headless_window(size, device_pixel_ratio_override);
+        window
+    }
+
+    /// The stable headless identifier of this window.
+    pub fn headless_id(&self) -> HeadlessWindowId {
+        self.id
+    }
+
+    fn new_headless(
+        size: Size2D<u32, DeviceIndependentPixel>,
+        device_pixel_ratio_override: Option<f32>,
+    ) -> Rc<Window> {
         // Initialize surfman
         let connection = Connection::new().expect("Failed to create connection");
         let adapter = connection
@@ -64,30 +199,197 @@ impl Window {
             |screen_size_override| screen_size_override.to_i32(),
         );
 
+        let (event_sender, external_events) = mpsc::channel();
+
         let window = Window {
+            id: HeadlessWindowId(NEXT_HEADLESS_WINDOW_ID.fetch_add(1, Ordering::Relaxed)),
             rendering_context,
             animation_state: Cell::new(AnimationState::Idle),
             fullscreen: Cell::new(false),
-            device_pixel_ratio_override,
+            device_pixel_ratio_override: Cell::new(device_pixel_ratio_override),
             inner_size,
+            page_zoom: Cell::new(1.0),
             screen_size,
             window_rect,
             event_queue: RwLock::new(Vec::new()),
+            event_sender,
+            external_events,
+            xr_rotation: Cell::new(Rotation3D::identity()),
+            xr_translation: Cell::new(Vector3D::zero()),
+            xr_surface_texture: RefCell::new(None),
         };
 
         Rc::new(window)
     }
+
+    /// Read back the contents of the software-rendered surfman surface into a
+    /// CPU-side, top-down RGBA8 buffer.
+    ///
+    /// Returns `None` when no surface is currently bound to the rendering
+    /// context. The returned size is the size of the surface that was read, and
+    /// the buffer is `size.width * size.height * 4` bytes long.
+    pub fn read_framebuffer(&self) -> Option<(DeviceIntSize, Vec<u8>)> {
+        let surface_info = self.rendering_context.context_surface_info().ok()??;
+        let size = DeviceIntSize::from_untyped(surface_info.size);
+
+        self.rendering_context.make_gl_context_current().ok()?;
+        let gl = self.rendering_context.gleam_gl_api();
+
+        // Read from the framebuffer backing the bound surface. surfman's
+        // software surfaces are BGRA8, so read in that order and swizzle to
+        // RGBA below; reading `gl::RGBA` directly would hand back a buffer with
+        // red and blue swapped.
+        let mut prev_framebuffer = [0];
+        gl.get_integer_v(gl::FRAMEBUFFER_BINDING, &mut prev_framebuffer);
+        gl.bind_framebuffer(gl::FRAMEBUFFER, surface_info.framebuffer_object);
+        let mut pixels = gl.read_pixels(
+            0,
+            0,
+            size.width,
+            size.height,
+            gl::BGRA,
+            gl::UNSIGNED_BYTE,
+        );
+
+        // Restore whatever the compositor had bound so the readback leaves no
+        // side effect on the GL state.
+        gl.bind_framebuffer(gl::FRAMEBUFFER, prev_framebuffer[0] as gl::GLuint);
+
+        // BGRA -> RGBA: swap the blue and red channel of every pixel.
+        for pixel in pixels.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+
+        // `glReadPixels` hands back rows bottom-up; flip whole rows so the
+        // buffer is top-down like every image consumer expects.
+        let stride = size.width as usize * 4;
+        if stride > 0 {
+            let height = size.height as usize;
+            for row in 0..height / 2 {
+                let top = row * stride;
+                let bottom = (height - 1 - row) * stride;
+                let (head, tail) = pixels.split_at_mut(bottom);
+                head[top..top + stride].swap_with_slice(&mut tail[..stride]);
+            }
+        }
+
+        Some((size, pixels))
+    }
+
+    /// Set the simulated head pose reported to WebXR content.
+    ///
+    /// A test harness can step this along a scripted camera path between frames
+    /// to exercise immersive content without real XR hardware.
+    pub fn set_pose(
+        &self,
+        rotation: Rotation3D<f32, UnknownUnit, UnknownUnit>,
+        translation: Vector3D<f32, UnknownUnit>,
+    ) {
+        self.xr_rotation.set(rotation);
+        self.xr_translation.set(translation);
+    }
+
+    /// Hand out a [`Sender`] for injecting [`EmbedderEvent`]s into this window
+    /// from another thread.
+    ///
+    /// `Window` is `!Send` (it owns an `Rc`-backed `RenderingContext`), so a
+    /// reference to it can never cross a thread boundary; the cloned `Sender`
+    /// is `Send` independently of the window. A controller thread can use it to
+    /// programmatically drive resizes, navigation, input and shutdown without a
+    /// winit event loop. Injected events are drained by the usual `get_events`
+    /// call on the owning thread.
+    pub fn event_sender(&self) -> Sender<EmbedderEvent> {
+        self.event_sender.clone()
+    }
+
+    /// Simulate a display scale change by overriding the device-pixel-ratio at
+    /// runtime.
+    ///
+    /// Updates the stored [`Scale`], recomputes the framebuffer size from the
+    /// current logical window size under the new scale, resizes the rendering
+    /// context to match and queues an embedder event so compositing and script
+    /// re-layout. `None` clears a previously-set override, falling back to
+    /// `device_hidpi_factor()`.
+    pub fn set_device_pixel_ratio_override(&self, dpr: Option<f32>) {
+        let old_factor = self.hidpi_factor();
+        self.device_pixel_ratio_override.set(dpr.map(Scale::new));
+        let new_factor = self.hidpi_factor();
+        if old_factor == new_factor {
+            return;
+        }
+
+        // Recover the logical window size from the *current* framebuffer under
+        // the previous scale, then re-apply the new scale. Deriving from
+        // `inner_size` rather than the construction-time `window_rect` keeps
+        // this correct across intervening `request_inner_size` calls.
+        let logical_size = self.inner_size.get().to_f32() / old_factor;
+        let scaled = (logical_size * new_factor).round().to_i32();
+        let new_size = DeviceIntSize::new(scaled.width.max(1), scaled.height.max(1));
+
+        // Resize the surface only when the framebuffer size actually changed...
+        if self.inner_size.get() != new_size {
+            match self.rendering_context.resize(new_size.to_untyped()) {
+                Ok(()) => self.inner_size.set(new_size),
+                Err(error) => {
+                    warn!("Could not resize window: {error:?}");
+                    return;
+                },
+            }
+        }
+
+        // ...but always signal the ratio change: even when the framebuffer size
+        // is unchanged, script must re-layout against the new
+        // `devicePixelRatio`. `EmbedderEvent` has no dedicated variant for this,
+        // so reuse `WindowResize`, which drives the same re-layout path.
+        if let Ok(ref mut queue) = self.event_queue.write() {
+            queue.push(EmbedderEvent::WindowResize);
+        }
+    }
+
+    /// Set the current page zoom, so headless-reported window geometry accounts
+    /// for zoom the same way a windowed backend does.
+    ///
+    /// Page zoom scales content independently of the device-pixel ratio; a zoom
+    /// of `1.0` leaves the reported device-independent window rect unchanged.
+    pub fn set_page_zoom(&self, zoom: f32) {
+        self.page_zoom.set(zoom);
+    }
+
+    /// Read the framebuffer and encode it as a PNG, for screenshot and diff
+    /// testing of headless sessions.
+    ///
+    /// Returns `None` if no surface is bound or the encode fails.
+    pub fn read_framebuffer_png(&self) -> Option<Vec<u8>> {
+        let (size, pixels) = self.read_framebuffer()?;
+        let mut png = Vec::new();
+        let encoder = image::codecs::png::PngEncoder::new(&mut png);
+        encoder
+            .write_image(
+                &pixels,
+                size.width as u32,
+                size.height as u32,
+                image::ColorType::Rgba8.into(),
+            )
+            .ok()?;
+        Some(png)
+    }
 }
 
 impl WindowPortsMethods for Window {
     fn get_events(&self) -> Vec<EmbedderEvent> {
-        match self.event_queue.write() {
-            Ok(ref mut event_queue) => std::mem::take(event_queue),
+        let mut events = match self.event_queue.write() {
+            Ok(mut event_queue) => std::mem::take(&mut *event_queue),
             Err(_) => vec![],
-        }
+        };
+        // Drain anything a controller thread pushed through `event_sender`.
+        events.extend(self.external_events.try_iter());
+        events
     }
 
     fn id(&self) -> winit::window::WindowId {
+        // winit won't mint a `WindowId` for a window it didn't create, so every
+        // headless window shares the dummy winit id; use `headless_id` to tell
+        // them apart.
         winit::window::WindowId::dummy()
     }
 
@@ -120,7 +422,7 @@ impl WindowPortsMethods for Window {
     fn device_pixel_ratio_override(
         &self,
     ) -> Option<Scale<f32, DeviceIndependentPixel, DevicePixel>> {
-        self.device_pixel_ratio_override
+        self.device_pixel_ratio_override.get()
     }
 
     fn page_height(&self) -> f32 {
@@ -172,14 +474,34 @@ impl WindowPortsMethods for Window {
 
 impl WindowMethods for Window {
     fn get_coordinates(&self) -> EmbedderCoordinates {
-        let inner_size = self.inner_size.get();
+        let hidpi_factor = self.hidpi_factor();
+        let page_zoom = self.page_zoom.get();
+
+        // The framebuffer and viewport live in device pixels: that is the size
+        // of the software surface compositing actually draws into.
+        let framebuffer = self.inner_size.get();
+        let viewport = Box2D::from_origin_and_size(Point2D::zero(), framebuffer);
+
+        // The window and screen rects, on the other hand, are reported to
+        // script (`window.outerWidth`/`outerHeight`, `screen.width`/`height`) in
+        // device-independent pixels. Derive the window rect from the device-pixel
+        // framebuffer through the device-pixel ratio *and* the current page
+        // zoom, rather than conflating the two pixel spaces: zooming in scales
+        // the content up, so fewer device-independent pixels fit in the same
+        // framebuffer. `screen_size_override` is likewise interpreted in
+        // device-independent space and is unaffected by page zoom.
+        let window_size = (framebuffer.to_f32() / hidpi_factor / page_zoom)
+            .round()
+            .to_i32();
+        let window_rect = Box2D::from_origin_and_size(self.window_rect.min, window_size);
+
         EmbedderCoordinates {
-            viewport: Box2D::from_origin_and_size(Point2D::zero(), inner_size),
-            framebuffer: inner_size,
-            window_rect: self.window_rect,
+            viewport,
+            framebuffer,
+            window_rect,
             screen_size: self.screen_size,
             available_screen_size: self.screen_size,
-            hidpi_factor: self.hidpi_factor(),
+            hidpi_factor,
         }
     }
 
@@ -192,20 +514,83 @@ impl WindowMethods for Window {
     }
 }
 
+impl Drop for Window {
+    fn drop(&mut self) {
+        // surfman requires surfaces to be torn down explicitly — dropping a
+        // retained `SurfaceTexture` panics — so release any render target still
+        // held from `get_render_target` through the rendering context's own
+        // device/context (the same pair the surface was allocated from) before
+        // the window goes away.
+        let Some(surface_texture) = self.xr_surface_texture.get_mut().take() else {
+            return;
+        };
+        let mut device = self.rendering_context.device();
+        let mut context = self.rendering_context.context();
+        match device.destroy_surface_texture(&mut context, surface_texture) {
+            Ok(surface) => {
+                if let Err(error) = device.destroy_surface(&mut context, surface) {
+                    warn!("Could not destroy headless WebXR surface on drop: {error:?}");
+                }
+            },
+            Err((error, _)) => {
+                warn!("Could not destroy headless WebXR surface texture on drop: {error:?}");
+            },
+        }
+    }
+}
+
 impl webxr::glwindow::GlWindow for Window {
     fn get_render_target(
         &self,
-        _device: &mut Device,
-        _context: &mut Context,
+        device: &mut Device,
+        context: &mut Context,
     ) -> webxr::glwindow::GlWindowRenderTarget {
-        unimplemented!()
+        // Tear down the surface backing any previously-vended render target.
+        // surfman requires explicit teardown — dropping a `SurfaceTexture`
+        // panics — and the retained surface must be released before allocating
+        // its replacement.
+        if let Some(surface_texture) = self.xr_surface_texture.borrow_mut().take() {
+            let surface = device
+                .destroy_surface_texture(context, surface_texture)
+                .map_err(|(error, _)| error)
+                .expect("Failed to destroy headless WebXR surface texture");
+            device
+                .destroy_surface(context, surface)
+                .expect("Failed to destroy headless WebXR surface");
+        }
+
+        // Allocate a software-backed surface wide enough for a side-by-side
+        // stereo viewport (left eye | right eye) and hand WebXR the GL texture
+        // that backs it, mirroring the windowed GlWindow's render target.
+        let inner_size = self.inner_size.get();
+        let size = DeviceIntSize::new(inner_size.width * 2, inner_size.height);
+
+        let surface_type = SurfaceType::Generic {
+            size: size.to_untyped(),
+        };
+        let surface = device
+            .create_surface(context, surfman::SurfaceAccess::GPUOnly, surface_type)
+            .expect("Failed to create headless WebXR surface");
+        let surface_texture = device
+            .create_surface_texture(context, surface)
+            .expect("Failed to create headless WebXR surface texture");
+        let texture = device.surface_texture_object(&surface_texture);
+
+        // Retain the surface-texture so its backing surface outlives the raw
+        // texture id we return to WebXR.
+        *self.xr_surface_texture.borrow_mut() = Some(surface_texture);
+
+        webxr::glwindow::GlWindowRenderTarget::NativeTexture {
+            texture,
+            size: size.to_untyped().cast_unit(),
+        }
     }
 
     fn get_rotation(&self) -> Rotation3D<f32, UnknownUnit, UnknownUnit> {
-        Rotation3D::identity()
+        self.xr_rotation.get()
     }
 
     fn get_translation(&self) -> Vector3D<f32, UnknownUnit> {
-        Vector3D::zero()
+        self.xr_translation.get()
     }
 }